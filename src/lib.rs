@@ -13,8 +13,12 @@
 // limitations under the License.
 
 use std::convert::TryInto;
+use std::hash::Hasher;
+use std::marker::PhantomData;
 
-use sha2::{Digest, Sha256};
+use digest::generic_array::typenum::U32;
+use digest::Digest;
+use sha2::Sha256;
 
 /// The number of bytes in the digest of both the hash used by setsum and the output
 /// of setsum.
@@ -67,28 +71,130 @@ fn hash_to_state(hash: &[u8; SETSUM_BYTES]) -> [u32; SETSUM_COLUMNS] {
     item_state
 }
 
-/// Translate a single item into the internal representation of a setsum.
-fn item_to_state(item: &[u8]) -> [u32; SETSUM_COLUMNS] {
-    let mut hasher = Sha256::default();
+/// Translate a single item into the internal representation of a setsum using the backend `H`,
+/// optionally mixing in a secret key first so that the resulting state cannot be reproduced
+/// without knowing the key.
+fn item_to_state_keyed<H: Digest<OutputSize = U32>>(
+    item: &[u8],
+    key: Option<&[u8; SETSUM_BYTES]>,
+) -> [u32; SETSUM_COLUMNS] {
+    let mut hasher = H::new();
+    if let Some(key) = key {
+        hasher.update(key);
+    }
     hasher.update(item);
-    let mut hash_bytes = hasher.finalize();
-    let hash_bytes: &mut [u8; SETSUM_BYTES] = hash_bytes.as_mut();
+    let hash_bytes = hasher.finalize();
+    let hash_bytes: &[u8; SETSUM_BYTES] = hash_bytes.as_slice().try_into().unwrap();
     hash_to_state(hash_bytes)
 }
 
-/// Setsum provides an interactive object for maintaining set checksums.  Technically, multi-set
-/// checksums.  Two Setsum objects are equal with high probability if and only if they contain the
-/// same items.
-#[derive(Debug, Eq, PartialEq)]
-pub struct Setsum {
+/// Adapts a `Digest` into a `std::hash::Hasher` so that any `T: std::hash::Hash` can be fed into
+/// a setsum item hash without hand-rolling a canonical byte encoding first.  `write` forwards
+/// straight into the wrapped digest's `update`; `finish` is never called by `Hash::hash` and is
+/// not meaningful here, so it is unimplemented.  Call `finalize` to get the real 32-byte output.
+struct DigestHasher<H: Digest<OutputSize = U32>> {
+    hasher: H,
+}
+
+impl<H: Digest<OutputSize = U32>> DigestHasher<H> {
+    fn new(key: Option<&[u8; SETSUM_BYTES]>) -> Self {
+        let mut hasher = H::new();
+        if let Some(key) = key {
+            hasher.update(key);
+        }
+        DigestHasher { hasher }
+    }
+
+    fn finalize(self) -> [u8; SETSUM_BYTES] {
+        let hash_bytes = self.hasher.finalize();
+        let hash_bytes: &[u8; SETSUM_BYTES] = hash_bytes.as_slice().try_into().unwrap();
+        *hash_bytes
+    }
+}
+
+impl<H: Digest<OutputSize = U32>> Hasher for DigestHasher<H> {
+    fn write(&mut self, bytes: &[u8]) {
+        Digest::update(&mut self.hasher, bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        unimplemented!("DigestHasher only accumulates bytes; call finalize() for the digest")
+    }
+}
+
+/// GenericSetsum provides an interactive object for maintaining set checksums.  Technically,
+/// multi-set checksums.  Two GenericSetsum objects are equal with high probability if and only if
+/// they contain the same items.
+///
+/// GenericSetsum is generic over the digest `H` used to turn each item into a 32-byte hash.  `H`
+/// must produce exactly 32 bytes of output so it fits the internal eight-column representation;
+/// `Sha256` is used by `Setsum`, a type alias for `GenericSetsum<Sha256>` kept around so existing
+/// call sites keep compiling, but a faster or non-cryptographic backend may be substituted by
+/// naming `GenericSetsum` explicitly, e.g. `GenericSetsum<MyFastHash>`.  Two setsums built from
+/// different backends are different types, so the compiler will reject attempts to add or
+/// subtract them.
+///
+/// A `GenericSetsum` built with `with_key` additionally mixes a secret key into every item hash,
+/// turning the digest into an unforgeable set authenticator: without the key, an adversary who
+/// controls inserted items cannot engineer a collision.  `add`/`sub` panic if the two operands
+/// were built with different keys, since combining them would otherwise mean something other than
+/// "the union/difference of these two multi-sets".
+pub struct GenericSetsum<H: Digest<OutputSize = U32> = Sha256> {
     state: [u32; SETSUM_COLUMNS],
+    key: Option<[u8; SETSUM_BYTES]>,
+    _phantom: PhantomData<H>,
 }
 
-impl Setsum {
+/// The default, SHA-256-backed setsum, and the type most callers want.  This is a real type
+/// alias, not a generic default parameter: a default generic parameter only kicks in when the
+/// type is named explicitly (e.g. `let x: Setsum = ...`), not when inferring an unannotated
+/// expression like `Setsum::default()`, so it would not actually keep unannotated call sites
+/// compiling.  A type alias substitutes before inference runs, so it does.  Use `GenericSetsum<H>`
+/// directly to swap in a different digest backend.
+pub type Setsum = GenericSetsum<Sha256>;
+
+// `GenericSetsum`'s fields don't actually depend on `H` at runtime, so these are implemented by
+// hand rather than derived: a derive would add a spurious `H: Debug + Eq + PartialEq` bound even
+// though `H` only ever appears inside a `PhantomData`.
+impl<H: Digest<OutputSize = U32>> std::fmt::Debug for GenericSetsum<H> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GenericSetsum")
+            .field("state", &self.state)
+            .field("key", &self.key)
+            .finish()
+    }
+}
+
+impl<H: Digest<OutputSize = U32>> PartialEq for GenericSetsum<H> {
+    fn eq(&self, other: &Self) -> bool {
+        self.state == other.state && self.key == other.key
+    }
+}
+
+impl<H: Digest<OutputSize = U32>> Eq for GenericSetsum<H> {}
+
+impl<H: Digest<OutputSize = U32>> GenericSetsum<H> {
+    /// Creates a keyed setsum.  Every item inserted into or removed from the returned setsum has
+    /// `key` mixed into its hash.  The empty state starts at the same all-zero additive identity
+    /// as an unkeyed setsum (see `Default`) rather than one derived from `key`, so that `+`/`-`
+    /// still combine independently-built keyed setsums into the union/difference of their
+    /// multi-sets; folding `key` into the initial state instead would make every keyed setsum
+    /// carry an extra key-derived term that gets double-counted by `+` and cancelled by `-`.
+    /// `key` alone (not the numeric state) is what distinguishes a keyed-empty setsum from an
+    /// unkeyed one; compare the `GenericSetsum` values themselves, not just `digest()`, to tell
+    /// them apart.
+    pub fn with_key(key: &[u8; SETSUM_BYTES]) -> GenericSetsum<H> {
+        GenericSetsum {
+            state: [0u32; SETSUM_COLUMNS],
+            key: Some(*key),
+            _phantom: PhantomData,
+        }
+    }
+
     /// Inserts a new item into the multi-set.  If the item was already inserted, it will be
     /// inserted again.
     pub fn insert(&mut self, item: &[u8]) {
-        let item_state = item_to_state(item);
+        let item_state = item_to_state_keyed::<H>(item, self.key.as_ref());
         self.state = add_state(self.state, item_state);
     }
 
@@ -97,11 +203,61 @@ impl Setsum {
     /// one insert of the item.  Multiple placeholders can accrue and all will be removed before the
     /// set matches a set in which the item was inserted.
     pub fn remove(&mut self, item: &[u8]) {
-        let item_state = item_to_state(item);
+        let item_state = item_to_state_keyed::<H>(item, self.key.as_ref());
         let item_state = invert_state(item_state);
         self.state = add_state(self.state, item_state);
     }
 
+    /// Inserts any `T: std::hash::Hash` into the multi-set by driving its `Hash` impl into the
+    /// item digest directly, without the caller hand-rolling a canonical byte encoding.  Note
+    /// that the multi-set identity of `value` is now whatever bytes `T`'s `Hash` impl chooses to
+    /// write, not any external serialization, so changing that impl changes what gets inserted.
+    pub fn insert_hashable<T: std::hash::Hash>(&mut self, value: &T) {
+        let mut hasher = DigestHasher::<H>::new(self.key.as_ref());
+        value.hash(&mut hasher);
+        let item_state = hash_to_state(&hasher.finalize());
+        self.state = add_state(self.state, item_state);
+    }
+
+    /// Removes any `T: std::hash::Hash` from the multi-set.  See `insert_hashable` for how `value`
+    /// is turned into an item hash.
+    pub fn remove_hashable<T: std::hash::Hash>(&mut self, value: &T) {
+        let mut hasher = DigestHasher::<H>::new(self.key.as_ref());
+        value.hash(&mut hasher);
+        let item_state = invert_state(hash_to_state(&hasher.finalize()));
+        self.state = add_state(self.state, item_state);
+    }
+
+    /// Returns a writer that hashes everything written to it as a single item, without requiring
+    /// the item's bytes to be buffered in memory.  Call `finish()` on the returned `ItemHasher` to
+    /// fold the streamed bytes into this setsum as one insert, equivalent to buffering the same
+    /// bytes and calling `insert`.
+    pub fn item_writer(&mut self) -> ItemHasher<'_, H> {
+        let mut hasher = H::new();
+        if let Some(key) = self.key {
+            hasher.update(key);
+        }
+        ItemHasher {
+            setsum: self,
+            hasher,
+            invert: false,
+        }
+    }
+
+    /// Like `item_writer`, but the streamed item is removed from the multi-set instead of
+    /// inserted, equivalent to buffering the same bytes and calling `remove`.
+    pub fn remove_writer(&mut self) -> ItemHasher<'_, H> {
+        let mut hasher = H::new();
+        if let Some(key) = self.key {
+            hasher.update(key);
+        }
+        ItemHasher {
+            setsum: self,
+            hasher,
+            invert: true,
+        }
+    }
+
     /// Computes a byte representation of the setsum for comparison or use in other situations.
     pub fn digest(&self) -> [u8; SETSUM_BYTES] {
         let mut item_hash = [0u8; SETSUM_BYTES];
@@ -114,32 +270,278 @@ impl Setsum {
         }
         item_hash
     }
+
+    /// Reconstructs an (unkeyed) setsum from a previously computed `digest()`, the inverse of
+    /// that method, so a checksum that was persisted as bytes can be loaded back and continue
+    /// accumulating.  Each decoded column is reduced modulo its prime for canonicalization, same
+    /// as `hash_to_state` does for a freshly hashed item.  Use `from_digest_with_key` to reload a
+    /// setsum that was built with `with_key`; reloading a keyed digest through this method would
+    /// silently downgrade it to unkeyed.
+    pub fn from_digest(digest: &[u8; SETSUM_BYTES]) -> GenericSetsum<H> {
+        GenericSetsum {
+            state: hash_to_state(digest),
+            key: None,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Like `from_digest`, but for a setsum that was built with `with_key`.  The caller must
+    /// supply the same key the digest was originally produced under; there is no way to recover
+    /// the key from the digest bytes alone.
+    pub fn from_digest_with_key(
+        digest: &[u8; SETSUM_BYTES],
+        key: &[u8; SETSUM_BYTES],
+    ) -> GenericSetsum<H> {
+        GenericSetsum {
+            state: hash_to_state(digest),
+            key: Some(*key),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Like `str::parse`, but for a setsum that was built with `with_key`.  The caller must supply
+    /// the same key the original setsum was built with.
+    pub fn parse_with_key(
+        s: &str,
+        key: &[u8; SETSUM_BYTES],
+    ) -> Result<GenericSetsum<H>, ParseSetsumError> {
+        let digest = parse_hex_digest(s)?;
+        Ok(Self::from_digest_with_key(&digest, key))
+    }
+}
+
+/// A write handle returned by `Setsum::item_writer`/`Setsum::remove_writer` that hashes bytes
+/// incrementally instead of requiring the whole item to be buffered up front.  Every byte written
+/// is fed into a single in-progress digest; calling `finish()` finalizes that digest and folds it
+/// into the originating setsum as exactly one item, just as if the streamed bytes had been passed
+/// to `insert`/`remove` in one call.
+pub struct ItemHasher<'a, H: Digest<OutputSize = U32>> {
+    setsum: &'a mut GenericSetsum<H>,
+    hasher: H,
+    invert: bool,
+}
+
+impl<'a, H: Digest<OutputSize = U32>> ItemHasher<'a, H> {
+    /// Finalizes the streamed item and folds it into the originating setsum.
+    pub fn finish(self) {
+        let hash_bytes = self.hasher.finalize();
+        let hash_bytes: &[u8; SETSUM_BYTES] = hash_bytes.as_slice().try_into().unwrap();
+        let mut item_state = hash_to_state(hash_bytes);
+        if self.invert {
+            item_state = invert_state(item_state);
+        }
+        self.setsum.state = add_state(self.setsum.state, item_state);
+    }
+}
+
+impl<'a, H: Digest<OutputSize = U32>> std::io::Write for ItemHasher<'a, H> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Digest::update(&mut self.hasher, buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
 }
 
-impl Default for Setsum {
-    fn default() -> Setsum {
-        Setsum {
+impl<H: Digest<OutputSize = U32>> Default for GenericSetsum<H> {
+    fn default() -> GenericSetsum<H> {
+        GenericSetsum {
             state: [0u32; SETSUM_COLUMNS],
+            key: None,
+            _phantom: PhantomData,
         }
     }
 }
 
-impl std::ops::Add<Setsum> for Setsum {
-    type Output = Setsum;
+impl<H: Digest<OutputSize = U32>> std::ops::Add<GenericSetsum<H>> for GenericSetsum<H> {
+    type Output = GenericSetsum<H>;
 
-    fn add(self, rhs: Setsum) -> Setsum {
+    fn add(self, rhs: GenericSetsum<H>) -> GenericSetsum<H> {
+        assert_eq!(
+            self.key, rhs.key,
+            "cannot combine setsums built with different keys"
+        );
         let state = add_state(self.state, rhs.state);
-        Setsum { state }
+        GenericSetsum {
+            state,
+            key: self.key,
+            _phantom: PhantomData,
+        }
     }
 }
 
-impl std::ops::Sub<Setsum> for Setsum {
-    type Output = Setsum;
+impl<H: Digest<OutputSize = U32>> std::ops::Sub<GenericSetsum<H>> for GenericSetsum<H> {
+    type Output = GenericSetsum<H>;
 
-    fn sub(self, rhs: Setsum) -> Setsum {
+    fn sub(self, rhs: GenericSetsum<H>) -> GenericSetsum<H> {
+        assert_eq!(
+            self.key, rhs.key,
+            "cannot combine setsums built with different keys"
+        );
         let rhs_state = invert_state(rhs.state);
         let state = add_state(self.state, rhs_state);
-        Setsum { state }
+        GenericSetsum {
+            state,
+            key: self.key,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<H: Digest<OutputSize = U32>> std::fmt::LowerHex for GenericSetsum<H> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for byte in self.digest().iter() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl<H: Digest<OutputSize = U32>> std::fmt::Display for GenericSetsum<H> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::LowerHex::fmt(self, f)
+    }
+}
+
+/// The error returned when parsing a `Setsum` from a hex string fails.
+#[derive(Debug, Eq, PartialEq)]
+pub enum ParseSetsumError {
+    /// The string was not exactly `SETSUM_BYTES * 2` hex characters long.
+    BadLength(usize),
+    /// The string contained a non-hex-digit byte.
+    BadHex,
+}
+
+impl std::fmt::Display for ParseSetsumError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseSetsumError::BadLength(len) => write!(
+                f,
+                "setsum hex string must be {} characters, got {}",
+                SETSUM_BYTES * 2,
+                len
+            ),
+            ParseSetsumError::BadHex => write!(f, "setsum hex string contained a non-hex byte"),
+        }
+    }
+}
+
+impl std::error::Error for ParseSetsumError {}
+
+/// Decodes a hex string into digest bytes, shared by `FromStr::from_str` and
+/// `Setsum::parse_with_key`.  Validates that every byte is an ASCII hex digit before slicing it by
+/// byte index: `s.len()` counts bytes, not characters, so a string containing a multi-byte UTF-8
+/// character can have the right byte length while landing a byte-index slice in the middle of a
+/// character, and `u8::from_str_radix` on its own would also accept a leading `+` sign.
+fn parse_hex_digest(s: &str) -> Result<[u8; SETSUM_BYTES], ParseSetsumError> {
+    if s.len() != SETSUM_BYTES * 2 {
+        return Err(ParseSetsumError::BadLength(s.len()));
+    }
+    if !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(ParseSetsumError::BadHex);
+    }
+    let bytes = s.as_bytes();
+    let mut digest = [0u8; SETSUM_BYTES];
+    for (idx, byte) in digest.iter_mut().enumerate() {
+        let hex = std::str::from_utf8(&bytes[idx * 2..idx * 2 + 2]).unwrap();
+        *byte = u8::from_str_radix(hex, 16).map_err(|_| ParseSetsumError::BadHex)?;
+    }
+    Ok(digest)
+}
+
+impl<H: Digest<OutputSize = U32>> std::str::FromStr for GenericSetsum<H> {
+    type Err = ParseSetsumError;
+
+    /// Parses an unkeyed setsum from its hex digest.  Use `Setsum::parse_with_key` to reload a
+    /// setsum that was built with `with_key`.
+    fn from_str(s: &str) -> Result<GenericSetsum<H>, ParseSetsumError> {
+        let digest = parse_hex_digest(s)?;
+        Ok(Self::from_digest(&digest))
+    }
+}
+
+/// A lane is reduced once it gets within one item's maximum possible contribution
+/// (`u32::MAX`) of overflowing a `u64`, which bounds how many items can accumulate between
+/// reductions to roughly `2^32` without risking overflow.
+const LANE_REDUCTION_THRESHOLD: u64 = u64::MAX - u32::MAX as u64;
+
+/// Accumulates many items into a setsum while deferring the per-item modular reduction that
+/// `Setsum::insert` performs on every call.  Each column's running sum is kept in a `u64` lane
+/// that is only reduced modulo its prime once it approaches overflow, instead of on every insert,
+/// which amortizes the reduction cost across the whole batch.  The result of `finish()` is
+/// bit-identical to inserting the same items one at a time via `Setsum::insert`/`remove`.  An
+/// accumulator built with `with_key` folds into a keyed setsum, mixing the same key into every
+/// item the same way `Setsum::with_key` does.
+pub struct BatchAccumulator<H: Digest<OutputSize = U32> = Sha256> {
+    lanes: [u64; SETSUM_COLUMNS],
+    key: Option<[u8; SETSUM_BYTES]>,
+    _phantom: PhantomData<H>,
+}
+
+impl<H: Digest<OutputSize = U32>> BatchAccumulator<H> {
+    /// Creates an empty accumulator.
+    pub fn new() -> Self {
+        BatchAccumulator {
+            lanes: [0u64; SETSUM_COLUMNS],
+            key: None,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Creates an empty accumulator that folds into a setsum keyed with `key`, mirroring
+    /// `Setsum::with_key`.
+    pub fn with_key(key: &[u8; SETSUM_BYTES]) -> Self {
+        BatchAccumulator {
+            lanes: [0u64; SETSUM_COLUMNS],
+            key: Some(*key),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Accumulates an item as though it were inserted into the eventual setsum.
+    pub fn insert(&mut self, item: &[u8]) {
+        self.fold(item_to_state_keyed::<H>(item, self.key.as_ref()));
+    }
+
+    /// Accumulates an item as though it were removed from the eventual setsum.
+    pub fn remove(&mut self, item: &[u8]) {
+        self.fold(invert_state(item_to_state_keyed::<H>(
+            item,
+            self.key.as_ref(),
+        )));
+    }
+
+    fn fold(&mut self, item_state: [u32; SETSUM_COLUMNS]) {
+        for i in 0..SETSUM_COLUMNS {
+            self.lanes[i] += item_state[i] as u64;
+            if self.lanes[i] >= LANE_REDUCTION_THRESHOLD {
+                self.lanes[i] %= SETSUM_PRIMES[i] as u64;
+            }
+        }
+    }
+
+    /// Reduces the accumulated lanes and folds the result into a single setsum, as if every
+    /// accumulated item had instead been inserted/removed one at a time.
+    pub fn finish(mut self) -> GenericSetsum<H> {
+        let mut state = [0u32; SETSUM_COLUMNS];
+        for i in 0..SETSUM_COLUMNS {
+            self.lanes[i] %= SETSUM_PRIMES[i] as u64;
+            state[i] = self.lanes[i] as u32;
+        }
+        let mut setsum: GenericSetsum<H> = match self.key {
+            Some(key) => GenericSetsum::with_key(&key),
+            None => GenericSetsum::default(),
+        };
+        setsum.state = add_state(setsum.state, state);
+        setsum
+    }
+}
+
+impl<H: Digest<OutputSize = U32>> Default for BatchAccumulator<H> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -223,7 +625,7 @@ mod tests {
             0x42c4b0e3, 0x141cfc98, 0xc8f4fb9a, 0x24b96f99, 0xe441ae27, 0x4c939b64, 0x1b9995a4,
             0x55b85278,
         ];
-        let returned: [u32; SETSUM_COLUMNS] = item_to_state(&[]);
+        let returned: [u32; SETSUM_COLUMNS] = item_to_state_keyed::<Sha256>(&[], None);
         assert_eq!(expected, returned)
     }
 
@@ -341,4 +743,335 @@ mod tests {
         let digest = setsum_empty.digest();
         assert_eq!(Setsum::default().digest(), digest);
     }
+
+    #[test]
+    fn insert_hashable_differs_from_insert_of_raw_bytes() {
+        let mut by_hash = Setsum::default();
+        by_hash.insert_hashable(&("this is the first value".to_string()));
+        let mut by_bytes = Setsum::default();
+        by_bytes.insert(b"this is the first value");
+        // A `String`'s `Hash` impl writes its bytes followed by a 0xff terminator, so the two
+        // item hashes are not expected to match; the insert/remove round-trip is checked by
+        // `insert_hashable_remove_hashable_round_trip`.
+        assert_ne!(by_bytes.digest(), by_hash.digest());
+    }
+
+    #[test]
+    fn insert_hashable_remove_hashable_round_trip() {
+        let mut setsum = Setsum::default();
+        setsum.insert_hashable(&1u64);
+        setsum.insert_hashable(&("two", 2u64));
+        setsum.insert_hashable(&vec![1, 2, 3]);
+        setsum.remove_hashable(&vec![1, 2, 3]);
+        setsum.remove_hashable(&("two", 2u64));
+        setsum.remove_hashable(&1u64);
+        assert_eq!(Setsum::default().digest(), setsum.digest());
+    }
+
+    #[test]
+    fn item_writer_matches_one_shot_insert() {
+        use std::io::Write;
+
+        let mut streamed = Setsum::default();
+        {
+            let mut writer = streamed.item_writer();
+            writer.write_all(b"this is the ").unwrap();
+            writer.write_all(b"first value").unwrap();
+            writer.finish();
+        }
+
+        let mut buffered = Setsum::default();
+        buffered.insert(b"this is the first value");
+
+        assert_eq!(buffered.digest(), streamed.digest());
+    }
+
+    #[test]
+    fn remove_writer_matches_one_shot_remove() {
+        use std::io::Write;
+
+        let mut setsum = Setsum::default();
+        setsum.insert(b"this is the first value");
+        {
+            let mut writer = setsum.remove_writer();
+            writer.write_all(b"this is the first value").unwrap();
+            writer.finish();
+        }
+
+        assert_eq!(Setsum::default().digest(), setsum.digest());
+    }
+
+    /// A small xorshift64 generator, used only to make the randomized batch tests below
+    /// deterministic without pulling in a `rand` dependency.
+    fn xorshift64(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    #[test]
+    fn batch_accumulator_matches_scalar_insert_for_random_batch() {
+        let mut rng = 0x243f6a8885a308d3u64;
+        let items: Vec<[u8; 8]> = (0..10_000)
+            .map(|_| xorshift64(&mut rng).to_le_bytes())
+            .collect();
+
+        let mut scalar = Setsum::default();
+        for item in &items {
+            scalar.insert(item);
+        }
+
+        let mut batch = BatchAccumulator::new();
+        for item in &items {
+            batch.insert(item);
+        }
+        let batched: Setsum = batch.finish();
+
+        assert_eq!(scalar.digest(), batched.digest());
+    }
+
+    #[test]
+    fn batch_accumulator_handles_multiple_interior_reductions() {
+        let mut rng = 0xabcdef0123456789u64;
+        let items: Vec<[u8; 8]> = (0..5_000)
+            .map(|_| xorshift64(&mut rng).to_le_bytes())
+            .collect();
+
+        // Before every chunk of items, raise each lane to just below the reduction threshold so
+        // that the chunk's first item pushes the lane across it: each lane is then reduced once
+        // per chunk, many times over, not just once in finish().
+        let mut batch: BatchAccumulator = BatchAccumulator::new();
+
+        // Oracle: sum every item's per-column contribution (plus every amount a lane was raised
+        // by) in full precision, then reduce once. Because modular reduction distributes over
+        // addition, this must equal whatever the lazily-reduced lanes converge to.
+        let mut totals = [0u128; SETSUM_COLUMNS];
+        for chunk in items.chunks(500) {
+            for (lane, total) in batch.lanes.iter_mut().zip(totals.iter_mut()) {
+                let raise = LANE_REDUCTION_THRESHOLD - 1 - *lane;
+                *lane += raise;
+                *total += raise as u128;
+            }
+            for item in chunk {
+                batch.insert(item);
+                let state = item_to_state_keyed::<Sha256>(item, None);
+                for (total, column) in totals.iter_mut().zip(state.iter()) {
+                    *total += *column as u128;
+                }
+            }
+        }
+        let mut expected = [0u32; SETSUM_COLUMNS];
+        for (expected, (total, prime)) in expected
+            .iter_mut()
+            .zip(totals.iter().zip(SETSUM_PRIMES.iter()))
+        {
+            *expected = (*total % *prime as u128) as u32;
+        }
+
+        let batched = batch.finish();
+
+        let expected_setsum = Setsum {
+            state: expected,
+            ..Setsum::default()
+        };
+        assert_eq!(expected_setsum.digest(), batched.digest());
+    }
+
+    #[test]
+    fn batch_accumulator_with_key_matches_scalar_keyed_insert() {
+        let key = [3u8; SETSUM_BYTES];
+        let mut rng = 0x9e3779b97f4a7c15u64;
+        let items: Vec<[u8; 8]> = (0..10_000)
+            .map(|_| xorshift64(&mut rng).to_le_bytes())
+            .collect();
+
+        let mut scalar = Setsum::with_key(&key);
+        for item in &items {
+            scalar.insert(item);
+        }
+
+        let mut batch: BatchAccumulator = BatchAccumulator::with_key(&key);
+        for item in &items {
+            batch.insert(item);
+        }
+        let batched: Setsum = batch.finish();
+
+        assert_eq!(scalar.digest(), batched.digest());
+    }
+
+    #[test]
+    fn keyed_empty_differs_from_unkeyed_empty() {
+        // `digest()` alone can't tell these apart: a keyed setsum's empty state is the same
+        // all-zero additive identity as an unkeyed one, by design (see `with_key`). Comparing the
+        // typed `Setsum` values, which also carry `key`, is what distinguishes them.
+        let key = [7u8; SETSUM_BYTES];
+        let keyed = Setsum::with_key(&key);
+        let unkeyed = Setsum::default();
+        assert_eq!(keyed.digest(), unkeyed.digest());
+        assert_ne!(keyed, unkeyed);
+    }
+
+    #[test]
+    fn keyed_setsums_combine_via_add_sub_like_unkeyed_ones() {
+        let key = [7u8; SETSUM_BYTES];
+
+        // Two independently-built keyed empties should combine back into a keyed empty, not
+        // double-count a key-derived base.
+        let combined_empties = Setsum::with_key(&key) + Setsum::with_key(&key);
+        assert_eq!(Setsum::with_key(&key).digest(), combined_empties.digest());
+
+        // Merging two independently-built keyed shards should match a single keyed setsum with
+        // all of their items inserted, exactly like the unkeyed `setsum_merge_two_sets` case.
+        let mut whole = Setsum::with_key(&key);
+        whole.insert(b"x1");
+        whole.insert(b"x2");
+        whole.insert(b"y1");
+
+        let mut a = Setsum::with_key(&key);
+        a.insert(b"x1");
+        a.insert(b"x2");
+
+        let mut b = Setsum::with_key(&key);
+        b.insert(b"y1");
+        let b_digest = b.digest();
+
+        assert_eq!(whole.digest(), (a + b).digest());
+
+        let mut whole_again = Setsum::with_key(&key);
+        whole_again.insert(b"x1");
+        whole_again.insert(b"x2");
+        whole_again.insert(b"y1");
+        let mut a_again = Setsum::with_key(&key);
+        a_again.insert(b"x1");
+        a_again.insert(b"x2");
+
+        assert_eq!(b_digest, (whole_again - a_again).digest());
+    }
+
+    #[test]
+    fn keyed_insert_remove_round_trips_to_keyed_empty() {
+        let key = [7u8; SETSUM_BYTES];
+        let mut setsum = Setsum::with_key(&key);
+        setsum.insert(b"this is the first value");
+        setsum.insert(b"this is the second value");
+        setsum.remove(b"this is the second value");
+        setsum.remove(b"this is the first value");
+        assert_eq!(Setsum::with_key(&key).digest(), setsum.digest());
+    }
+
+    #[test]
+    fn different_keys_produce_different_digests_for_the_same_items() {
+        let mut a = Setsum::with_key(&[1u8; SETSUM_BYTES]);
+        let mut b = Setsum::with_key(&[2u8; SETSUM_BYTES]);
+        a.insert(b"this is the first value");
+        b.insert(b"this is the first value");
+        assert_ne!(a.digest(), b.digest());
+    }
+
+    #[test]
+    #[should_panic(expected = "different keys")]
+    fn combining_mismatched_keys_panics() {
+        let a = Setsum::with_key(&[1u8; SETSUM_BYTES]);
+        let b = Setsum::with_key(&[2u8; SETSUM_BYTES]);
+        let _ = a + b;
+    }
+
+    #[test]
+    fn from_digest_round_trips_through_digest() {
+        let mut setsum = Setsum::default();
+        setsum.insert(b"this is the first value");
+        setsum.insert(b"this is the second value");
+
+        let reloaded = Setsum::from_digest(&setsum.digest());
+        assert_eq!(setsum.digest(), reloaded.digest());
+    }
+
+    #[test]
+    fn from_digest_allows_continued_accumulation() {
+        let mut setsum = Setsum::default();
+        setsum.insert(b"this is the first value");
+
+        let mut reloaded = Setsum::from_digest(&setsum.digest());
+        reloaded.insert(b"this is the second value");
+
+        let mut expected = Setsum::default();
+        expected.insert(b"this is the first value");
+        expected.insert(b"this is the second value");
+
+        assert_eq!(expected.digest(), reloaded.digest());
+    }
+
+    #[test]
+    fn display_emits_64_char_lowercase_hex() {
+        let mut setsum = Setsum::default();
+        setsum.insert(b"this is the first value");
+        let text = setsum.to_string();
+
+        let expected: String = setsum
+            .digest()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(expected, text);
+        assert_eq!(64, text.len());
+        assert!(text
+            .chars()
+            .all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase()));
+    }
+
+    #[test]
+    fn display_then_parse_round_trips() {
+        let mut setsum = Setsum::default();
+        setsum.insert(b"this is the first value");
+        setsum.insert(b"this is the second value");
+
+        let text = setsum.to_string();
+        let parsed: Setsum = text.parse().unwrap();
+        assert_eq!(setsum.digest(), parsed.digest());
+    }
+
+    #[test]
+    fn parse_rejects_bad_length() {
+        let err: Result<Setsum, _> = "abcd".parse();
+        assert_eq!(Err(ParseSetsumError::BadLength(4)), err);
+    }
+
+    #[test]
+    fn parse_rejects_non_hex() {
+        let bad = "g".repeat(SETSUM_BYTES * 2);
+        let err: Result<Setsum, _> = bad.parse();
+        assert_eq!(Err(ParseSetsumError::BadHex), err);
+    }
+
+    #[test]
+    fn parse_rejects_non_ascii_without_panicking() {
+        // Byte-length 64 but not 64 hex characters: a multi-byte char lands a naive byte-index
+        // slice in the middle of a character instead of on a char boundary.
+        let mut bad = String::from("0");
+        bad.push('\u{20ac}');
+        bad.push_str(&"0".repeat(SETSUM_BYTES * 2 - 1 - '\u{20ac}'.len_utf8()));
+        assert_eq!(bad.len(), SETSUM_BYTES * 2);
+        let err: Result<Setsum, _> = bad.parse();
+        assert_eq!(Err(ParseSetsumError::BadHex), err);
+    }
+
+    #[test]
+    fn keyed_setsum_round_trips_through_digest_and_parsing() {
+        let key = [9u8; SETSUM_BYTES];
+        let mut setsum = Setsum::with_key(&key);
+        setsum.insert(b"this is the first value");
+
+        let mut via_digest = Setsum::from_digest_with_key(&setsum.digest(), &key);
+        let mut via_str = Setsum::parse_with_key(&setsum.to_string(), &key).unwrap();
+        via_digest.insert(b"this is the second value");
+        via_str.insert(b"this is the second value");
+
+        let mut expected = Setsum::with_key(&key);
+        expected.insert(b"this is the first value");
+        expected.insert(b"this is the second value");
+
+        assert_eq!(expected.digest(), via_digest.digest());
+        assert_eq!(expected.digest(), via_str.digest());
+    }
 }